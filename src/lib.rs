@@ -1,9 +1,11 @@
-//! Driver for Sensirion SHTC1 digital humidity sensor
+//! Driver for the Sensirion SHTC1 and SHTC3 digital humidity sensors
 
 #![no_std]
 
 extern crate byteorder;
 extern crate embedded_hal;
+#[cfg(feature = "libm")]
+extern crate libm;
 
 use byteorder::{ByteOrder, BigEndian};
 
@@ -13,9 +15,25 @@ use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
 const CRC8_POLYNOMIAL: u8 = 0x31;
 const I2C_ADDRESS: u8 = 0x70;
 
+/// Mask over the bits of the ID register that identify the chip variant
+const ID_MASK: u16 = 0x083F;
+/// Fixed ID pattern reported by the SHTC1
+const ID_SHTC1: u16 = 0x0007;
+/// Fixed ID pattern reported by the SHTC3
+const ID_SHTC3: u16 = 0x0807;
+
+/// Delay required after waking the sensor up before it will accept a measurement
+/// command, rounded up from the datasheet's ~240 us.
+const WAKE_UP_DELAY_MS: u8 = 1;
+
 pub struct SHTC1<I2C, D> {
     i2c: I2C,
     delay: D,
+    variant: Variant,
+    sleeping: bool,
+    /// Configuration of the measurement started by `start_measurement`, if any has not
+    /// yet been collected by `read_measurement`
+    pending: Option<MeasurementConfig>,
 }
 
 impl<I2C, D, E> SHTC1<I2C, D>
@@ -23,9 +41,47 @@ where
     I2C: Read<Error = E> + Write<Error = E> + WriteRead<Error = E>,
     D: DelayMs<u8>,
 {
-	/// Creates a new driver
-    pub fn new(i2c: I2C, delay: D) -> Self {
-        SHTC1 { i2c, delay }
+	/// Creates a new driver, detecting whether the attached chip is an SHTC1 or an SHTC3
+	/// by reading its ID register
+    pub fn new(i2c: I2C, delay: D) -> Result<Self, Error<E>> {
+        let mut dev = SHTC1 { i2c, delay, variant: Variant::Shtc1, sleeping: false, pending: None };
+        let id = dev.read_id()?;
+        dev.variant = match id & ID_MASK {
+            ID_SHTC1 => Variant::Shtc1,
+            ID_SHTC3 => Variant::Shtc3,
+            _ => return Err(Error::UnknownDevice),
+        };
+        Ok(dev)
+    }
+
+    /// Returns the chip variant that was detected at construction time
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    /// Put the sensor to sleep. No commands other than `wake_up` are accepted while asleep.
+    ///
+    /// Returns `Error::NotSupported` on an SHTC1, which has no sleep mode.
+    pub fn sleep(&mut self) -> Result<(), Error<E>> {
+        if self.variant != Variant::Shtc3 {
+            return Err(Error::NotSupported);
+        }
+        self.command(Command::Sleep)?;
+        self.sleeping = true;
+        Ok(())
+    }
+
+    /// Wake the sensor back up from sleep
+    ///
+    /// Returns `Error::NotSupported` on an SHTC1, which has no sleep mode.
+    pub fn wake_up(&mut self) -> Result<(), Error<E>> {
+        if self.variant != Variant::Shtc3 {
+            return Err(Error::NotSupported);
+        }
+        self.command(Command::WakeUp)?;
+        self.delay.delay_ms(WAKE_UP_DELAY_MS);
+        self.sleeping = false;
+        Ok(())
     }
 
 	/// Send an I2C command
@@ -35,19 +91,58 @@ where
             .map_err(Error::I2c)
     }
 
-    /// Take a temperature and humidity measurement
-    pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
-        let raw = self.measure_raw()?;
+    /// Take a temperature and humidity measurement with the given configuration
+    pub fn measure(&mut self, config: MeasurementConfig) -> Result<Measurement, Error<E>> {
+        let raw = self.measure_raw(config)?;
         Ok(convert(&raw))
     }
 
-    /// Take a temperature and humidity measurement
-    pub fn measure_raw(&mut self) -> Result<MeasurementRaw, Error<E>> {
-        self.command(Command::Measure(ClockStretch::Disabled, MeasurementOrder::TFirst))?;
-        self.delay.delay_ms(15);
+    /// Take a temperature and humidity measurement with the given configuration, blocking
+    /// until the conversion completes. Implemented on top of `start_measurement` and
+    /// `read_measurement`.
+    pub fn measure_raw(&mut self, config: MeasurementConfig) -> Result<MeasurementRaw, Error<E>> {
+        self.start_measurement(config)?;
+        let conversion_time_ms = self.min_conversion_time_ms(config.power_mode);
+        if let ClockStretch::Disabled = config.clock_stretch {
+            // With clock stretching the subsequent read blocks on its own; otherwise
+            // wait out the conversion time ourselves before polling.
+            self.delay.delay_ms(conversion_time_ms);
+        }
+        self.read_measurement(conversion_time_ms)
+    }
+
+    /// Send the command to start a measurement with the given configuration, without
+    /// waiting for it to complete. Use `min_conversion_time_ms` to know how long to wait
+    /// before calling `read_measurement`, which makes this suitable for cooperative
+    /// schedulers and async executors that cannot block on `delay_ms`.
+    pub fn start_measurement(&mut self, config: MeasurementConfig) -> Result<(), Error<E>> {
+        if self.sleeping {
+            self.wake_up()?;
+        }
+        self.command(Command::Measure(config.clock_stretch, config.order, config.power_mode))?;
+        self.pending = Some(config);
+        Ok(())
+    }
+
+    /// Read back the result of a measurement previously started with `start_measurement`.
+    ///
+    /// `elapsed_ms` is the time the caller has waited since `start_measurement`; since this
+    /// driver has no clock of its own, it trusts the caller to track it (e.g. against a
+    /// free-running timer) and compares it against `min_conversion_time_ms` itself rather
+    /// than relying on the I2C layer to signal readiness. With clock stretching disabled,
+    /// returns `Error::WouldBlock` if the conversion time has not elapsed yet; with clock
+    /// stretching enabled, `elapsed_ms` is ignored and the read is issued at once, the
+    /// sensor holding the bus until the conversion completes. Returns `Error::NotReady` if
+    /// no measurement is currently in progress.
+    pub fn read_measurement(&mut self, elapsed_ms: u8) -> Result<MeasurementRaw, Error<E>> {
+        let config = self.pending.ok_or(Error::NotReady)?;
+        let is_disabled = matches!(config.clock_stretch, ClockStretch::Disabled);
+        if is_disabled && elapsed_ms < self.min_conversion_time_ms(config.power_mode) {
+            return Err(Error::WouldBlock);
+        }
+        self.pending = None;
         let mut buf = [0; 6];
-        self.i2c.read(I2C_ADDRESS, &mut buf)
-                .map_err(Error::I2c)?;
+        self.i2c.read(I2C_ADDRESS, &mut buf).map_err(Error::I2c)?;
         self.validate_crc(&buf[0..3])?;
         self.validate_crc(&buf[3..6])?;
         let temperature = BigEndian::read_u16(&buf[0..2]);
@@ -55,6 +150,12 @@ where
         Ok(MeasurementRaw{ temperature, humidity })
     }
 
+    /// Minimum time to wait after `start_measurement` before `read_measurement` will
+    /// succeed, for the given power mode on the detected chip variant
+    pub fn min_conversion_time_ms(&self, power_mode: PowerMode) -> u8 {
+        measure_delay_ms(self.variant, power_mode)
+    }
+
     /// Read the ID register
     pub fn read_id(&mut self) -> Result<u16, Error<E>> {
         self.command(Command::ReadID)?;
@@ -83,6 +184,18 @@ where
     }
 }
 
+/// Returns the maximum conversion time for the given chip variant and power mode
+fn measure_delay_ms(variant: Variant, power_mode: PowerMode) -> u8 {
+    match (variant, power_mode) {
+        // Rounded up from the datasheet's 14.4 ms / 0.94 ms
+        (Variant::Shtc1, PowerMode::Normal) => 15,
+        (Variant::Shtc1, PowerMode::LowPower) => 1,
+        // Rounded up from the datasheet's 12.1 ms / 0.8 ms
+        (Variant::Shtc3, PowerMode::Normal) => 13,
+        (Variant::Shtc3, PowerMode::LowPower) => 1,
+    }
+}
+
 /// Convert MeasurementRaw to Measurement
 pub fn convert(m: &MeasurementRaw) -> Measurement {
     Measurement{
@@ -99,6 +212,33 @@ fn convert_humidity(raw: u16) -> i32 {
     (10000 * raw as i32) / 65535
 }
 
+/// Convert MeasurementRaw to MeasurementMicro, keeping the fractional part of the
+/// 16-bit sample that the hundredths-of-a-unit `convert` throws away
+pub fn convert_precise(m: &MeasurementRaw) -> MeasurementMicro {
+    MeasurementMicro {
+        temperature_udeg: convert_temperature_precise(m.temperature),
+        humidity_um: convert_humidity_precise(m.humidity),
+    }
+}
+
+// Splits `tmp` (a value scaled by 0x10000 per unit) into whole units and a micro-unit
+// remainder without floating point, using `x * 1_000_000 / 65536 == x * 15625 / 1024`.
+fn split_micro(tmp: i32) -> i32 {
+    let whole = tmp / 0x10000;
+    let micro = ((tmp % 0x10000) * 15625) / 1024;
+    whole * 1_000_000 + micro
+}
+
+fn convert_temperature_precise(raw: u16) -> i32 {
+    let tmp = raw as i32 * 175 - (45 << 16);
+    split_micro(tmp)
+}
+
+fn convert_humidity_precise(raw: u16) -> i32 {
+    let tmp = raw as i32 * 100;
+    split_micro(tmp)
+}
+
 fn crc8(data: &[u8]) -> u8 {
     let mut crc: u8 = 0xff;
     for byte in data {
@@ -121,15 +261,53 @@ pub enum Error<E> {
     Crc,
     /// I2C bus error
     I2c(E),
+    /// The ID register did not match a known SHTC1/SHTC3 part
+    UnknownDevice,
+    /// The operation is not supported by the detected chip variant (e.g. sleep on an SHTC1)
+    NotSupported,
+    /// `read_measurement` was called without a preceding `start_measurement`
+    NotReady,
+    /// `read_measurement` was called before the conversion time had elapsed; wait and
+    /// poll again
+    WouldBlock,
 }
 
 enum Command {
-    Measure(ClockStretch, MeasurementOrder),
+    Measure(ClockStretch, MeasurementOrder, PowerMode),
     SoftReset,
     ReadID,
+    Sleep,
+    WakeUp,
+}
+
+/// The detected chip variant
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    Shtc1,
+    Shtc3,
+}
+
+/// Configuration for a single measurement
+#[derive(Copy, Clone)]
+pub struct MeasurementConfig {
+    pub clock_stretch: ClockStretch,
+    pub order: MeasurementOrder,
+    pub power_mode: PowerMode,
+}
+
+impl Default for MeasurementConfig {
+    /// Clock stretching disabled, temperature first, normal power mode
+    fn default() -> Self {
+        MeasurementConfig {
+            clock_stretch: ClockStretch::Disabled,
+            order: MeasurementOrder::TFirst,
+            power_mode: PowerMode::Normal,
+        }
+    }
 }
 
-enum ClockStretch {
+#[derive(Copy, Clone)]
+pub enum ClockStretch {
     Enabled,
     Disabled,
 }
@@ -140,30 +318,87 @@ pub enum MeasurementOrder {
     HFirst,
 }
 
+/// Measurement power mode. Low power mode trades accuracy for a much shorter
+/// conversion time.
+#[derive(Copy, Clone)]
+pub enum PowerMode {
+    Normal,
+    LowPower,
+}
+
 #[derive(Debug)]
 pub struct Measurement {
     pub temperature: i32,
     pub humidity: i32,
 }
 
+#[cfg(feature = "libm")]
+impl Measurement {
+    /// Dew point, in hundredths of °C, via the Magnus formula. Returns `None` if
+    /// humidity is zero, since the dew point is undefined when there is no moisture.
+    pub fn dew_point(&self) -> Option<i32> {
+        if self.humidity <= 0 {
+            return None;
+        }
+        let t = self.temperature as f32 / 100.0;
+        let rh = self.humidity as f32 / 100.0;
+        let gamma = libm::logf(rh / 100.0) + (MAGNUS_A * t) / (MAGNUS_B + t);
+        let dew_point = (MAGNUS_B * gamma) / (MAGNUS_A - gamma);
+        Some((dew_point * 100.0) as i32)
+    }
+
+    /// Absolute humidity, in hundredths of g/m³, derived from temperature and relative
+    /// humidity. Zero when relative humidity is zero.
+    pub fn absolute_humidity(&self) -> i32 {
+        if self.humidity <= 0 {
+            return 0;
+        }
+        let t = self.temperature as f32 / 100.0;
+        let rh = self.humidity as f32 / 100.0;
+        let saturation_vapor_pressure = 6.112 * libm::expf((MAGNUS_A * t) / (MAGNUS_B + t));
+        let absolute_humidity = 216.7 * ((rh / 100.0) * saturation_vapor_pressure) / (273.15 + t);
+        (absolute_humidity * 100.0) as i32
+    }
+}
+
+#[cfg(feature = "libm")]
+const MAGNUS_A: f32 = 17.62;
+#[cfg(feature = "libm")]
+const MAGNUS_B: f32 = 243.12;
+
 #[derive(Debug)]
 pub struct MeasurementRaw {
     pub temperature: u16,
     pub humidity: u16,
 }
 
+/// Temperature and humidity at full sensor resolution, in fixed-point micro-units
+#[derive(Debug)]
+pub struct MeasurementMicro {
+    /// Temperature in micro-degrees Celsius
+    pub temperature_udeg: i32,
+    /// Relative humidity in micro-percent
+    pub humidity_um: i32,
+}
+
 impl Command {
     fn value(&self) -> [u8; 2] {
         use ClockStretch::Enabled as CSEnabled;
         use ClockStretch::Disabled as CSDisabled;
         use MeasurementOrder::*;
+        use PowerMode::Normal as PMNormal;
+        use PowerMode::LowPower as PMLowPower;
         match *self {
             // 5.2 Measurement Commands
             // Table 9
-            Command::Measure(CSEnabled,  TFirst) => [0x7Cu8, 0xA2u8],
-            Command::Measure(CSEnabled,  HFirst) => [0x5Cu8, 0x24u8],
-            Command::Measure(CSDisabled, TFirst) => [0x78u8, 0x66u8],
-            Command::Measure(CSDisabled, HFirst) => [0x58u8, 0xE0u8],
+            Command::Measure(CSEnabled,  TFirst, PMNormal)   => [0x7Cu8, 0xA2u8],
+            Command::Measure(CSEnabled,  HFirst, PMNormal)   => [0x5Cu8, 0x24u8],
+            Command::Measure(CSDisabled, TFirst, PMNormal)   => [0x78u8, 0x66u8],
+            Command::Measure(CSDisabled, HFirst, PMNormal)   => [0x58u8, 0xE0u8],
+            Command::Measure(CSEnabled,  TFirst, PMLowPower) => [0x64u8, 0x58u8],
+            Command::Measure(CSEnabled,  HFirst, PMLowPower) => [0x44u8, 0xDEu8],
+            Command::Measure(CSDisabled, TFirst, PMLowPower) => [0x60u8, 0x9Cu8],
+            Command::Measure(CSDisabled, HFirst, PMLowPower) => [0x40u8, 0x1Au8],
 
             // 5.6 Soft Reset
             // Table 10
@@ -172,6 +407,10 @@ impl Command {
             // 5.7 Read-out of ID register
             // Table 11
             Command::ReadID  => [0xEF, 0xC8],
+
+            // Sleep / Wake-up (SHTC3 only)
+            Command::Sleep   => [0xB0, 0x98],
+            Command::WakeUp  => [0x35, 0x17],
         }
     }
 }
@@ -184,4 +423,58 @@ mod tests {
         assert_eq!(crc8(&[0x00u8]), 0xAC);
         assert_eq!(crc8(&[0xBEu8, 0xEFu8]), 0x92);
     }
+
+    #[test]
+    fn convert_precise_min() {
+        let m = convert_precise(&MeasurementRaw { temperature: 0, humidity: 0 });
+        assert_eq!(m.temperature_udeg, -45_000_000);
+        assert_eq!(m.humidity_um, 0);
+    }
+
+    #[test]
+    fn convert_precise_max() {
+        let m = convert_precise(&MeasurementRaw { temperature: 65535, humidity: 65535 });
+        assert_eq!(m.temperature_udeg, 129_997_329);
+        assert_eq!(m.humidity_um, 99_998_474);
+    }
+
+    #[test]
+    fn convert_precise_mid() {
+        let m = convert_precise(&MeasurementRaw { temperature: 32768, humidity: 32768 });
+        assert_eq!(m.temperature_udeg, 42_500_000);
+        assert_eq!(m.humidity_um, 50_000_000);
+    }
+
+    #[cfg(feature = "libm")]
+    #[test]
+    fn dew_point_zero_humidity() {
+        let m = Measurement { temperature: 2500, humidity: 0 };
+        assert_eq!(m.dew_point(), None);
+    }
+
+    #[cfg(feature = "libm")]
+    #[test]
+    fn absolute_humidity_zero_humidity() {
+        let m = Measurement { temperature: 2500, humidity: 0 };
+        assert_eq!(m.absolute_humidity(), 0);
+    }
+
+    #[cfg(feature = "libm")]
+    #[test]
+    fn dew_point_reference_pair() {
+        // 25 °C at 50 %RH has a textbook dew point of ~13.86 °C
+        let m = Measurement { temperature: 2500, humidity: 5000 };
+        let dew_point = m.dew_point().unwrap();
+        assert!((1375..1395).contains(&dew_point), "dew_point = {}", dew_point);
+    }
+
+    #[cfg(feature = "libm")]
+    #[test]
+    fn dew_point_and_absolute_humidity_high_rh_high_t() {
+        let m = Measurement { temperature: 3500, humidity: 9000 };
+        let dew_point = m.dew_point().unwrap();
+        assert!((3300..3330).contains(&dew_point), "dew_point = {}", dew_point);
+        let absolute_humidity = m.absolute_humidity();
+        assert!((3540..3570).contains(&absolute_humidity), "absolute_humidity = {}", absolute_humidity);
+    }
 }